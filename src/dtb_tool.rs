@@ -1,11 +1,13 @@
+use aml_dtbtools::{table_size, AmlDtbImage, Header, HeaderEntry, ToWriter, TocEntry, AML_DT_HEADER};
 use clap::Parser;
 use flate2::read::GzDecoder;
-use std::cmp::min;
+use flate2::write::GzEncoder;
+use flate2::Compression as GzLevel;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::prelude::*;
 use std::io::{self, SeekFrom, Write};
-use std::mem::size_of;
-use std::str;
 use std::str::FromStr;
 use std::{path, vec};
 
@@ -14,11 +16,30 @@ const DT_ID_TAG: &str = "amlogic-dt-id";
 const PAGE_SIZE_DEF: usize = 2048;
 const PAGE_SIZE_MAX: usize = 1024 * 1024;
 // const COPY_BLK: usize = 1024;
-const INFO_ENTRY_SIZE: usize = 16;
 
-const AML_DT_MAGIC: &[u8; 4] = b"AML_";
-const AML_DT_HEADER: u32 = 0x5f4c4d41;
-const DT_HEADER_MAGIC: u32 = 0xedfe0dd0;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: [u8; 6] = [0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+        };
+        f.write_str(s)
+    }
+}
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -27,7 +48,60 @@ pub(crate) struct SplitArgs {
     boot_img_path: String,
 
     #[arg(short, long)]
-    dest: String,
+    dest: Option<String>,
+
+    #[arg(long)]
+    verify: Option<String>,
+
+    /// Only extract the entry whose decoded `soc-plat-vari` id matches exactly.
+    #[arg(long)]
+    id: Option<String>,
+    /// Only extract entries whose `soc` component matches.
+    #[arg(long)]
+    soc: Option<String>,
+    /// Only extract entries whose `plat` component matches.
+    #[arg(long)]
+    plat: Option<String>,
+    /// Only extract entries whose `vari` component matches.
+    #[arg(long)]
+    vari: Option<String>,
+    /// Write the matched entry to stdout instead of a `.dtb` file.
+    #[arg(long)]
+    stdout: bool,
+}
+
+/// Filters which entries `dump_entries` extracts, set from `--id`/`--soc`/
+/// `--plat`/`--vari`. An empty selector (no flags given) matches everything.
+struct Selector {
+    id: Option<String>,
+    soc: Option<String>,
+    plat: Option<String>,
+    vari: Option<String>,
+    stdout: bool,
+}
+
+impl Selector {
+    fn from_args(args: &SplitArgs) -> Option<Self> {
+        if args.id.is_none() && args.soc.is_none() && args.plat.is_none() && args.vari.is_none() {
+            return None;
+        }
+        Some(Self {
+            id: args.id.clone(),
+            soc: args.soc.clone(),
+            plat: args.plat.clone(),
+            vari: args.vari.clone(),
+            stdout: args.stdout,
+        })
+    }
+
+    fn matches(&self, soc: &str, plat: &str, vari: &str, id: &str) -> bool {
+        if let Some(want) = &self.id {
+            return want == id;
+        }
+        self.soc.as_deref().is_none_or(|w| w == soc)
+            && self.plat.as_deref().is_none_or(|w| w == plat)
+            && self.vari.as_deref().is_none_or(|w| w == vari)
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -39,135 +113,136 @@ pub(crate) struct PackArgs {
     page_size: u32,
     #[arg(short, long)]
     input_dir: String,
+    #[arg(short = 'c', long, value_enum, default_value_t = Compression::None)]
+    compress: Compression,
+    #[arg(short = 'm', long)]
+    manifest: Option<String>,
 }
 
-#[repr(C)]
-struct DTHeader {
-    magic: u32,
-    totalsize: u32,
+/// Splits a decoded `soc-plat-vari` id back into its parts for `Selector`
+/// matching.
+fn split_id(id: &str) -> (&str, &str, &str) {
+    let mut parts = id.splitn(3, '-');
+    (
+        parts.next().unwrap_or(""),
+        parts.next().unwrap_or(""),
+        parts.next().unwrap_or(""),
+    )
 }
 
-#[repr(C)]
-struct Header {
-    magic: u32,
-    version: u32,
-    entry_count: u32,
-}
-
-#[repr(C)]
-struct HeaderEntry<const ID_SIZE: usize> {
-    soc: [u8; ID_SIZE],
-    plat: [u8; ID_SIZE],
-    vari: [u8; ID_SIZE],
-    offset: u32,
-    dtb_size: u32,
-}
-
-impl<const ID_SIZE: usize> HeaderEntry<ID_SIZE> {
-    fn new() -> HeaderEntry<{ ID_SIZE }> {
-        Self {
-            soc: [0; ID_SIZE],
-            plat: [0; ID_SIZE],
-            vari: [0; ID_SIZE],
-            offset: 0,
-            dtb_size: 0,
+fn dump_entries(
+    image: &AmlDtbImage,
+    dest: Option<&str>,
+    manifest: Option<&HashMap<String, ManifestEntry>>,
+    selector: Option<&Selector>,
+) -> io::Result<bool> {
+    let mut all_ok = true;
+    let to_stdout = selector.is_some_and(|s| s.stdout);
+
+    for entry in &image.entries {
+        let (soc, plat, vari) = split_id(&entry.id);
+
+        if let Some(sel) = selector {
+            if !sel.matches(soc, plat, vari, &entry.id) {
+                continue;
+            }
         }
-    }
-}
 
-pub trait AsByteSlice {
-    fn as_slice(self: &Self) -> &[u8]
-    where
-        Self: Sized,
-    {
-        unsafe {
-            core::slice::from_raw_parts(
-                (self as *const Self) as *const u8,
-                ::core::mem::size_of::<Self>(),
-            )
+        // When the blob itself goes to stdout, every diagnostic has to go to
+        // stderr instead, or it corrupts the piped bytes.
+        if to_stdout {
+            eprintln!("Found header: {}", entry.id);
+            eprintln!("\t offset: {} size: {}", entry.offset, entry.size);
+            io::stdout().write_all(&entry.dtb)?;
+        } else {
+            println!("Found header: {}", entry.id);
+            println!("\t offset: {} size: {}", entry.offset, entry.size);
+            let output_path = format!("{}{}.dtb", dest.unwrap_or(""), entry.id);
+            let mut output = File::create(output_path)?;
+            output.write_all(&entry.dtb)?;
         }
-    }
-    fn as_mut_slice(self: &mut Self) -> &mut [u8]
-    where
-        Self: Sized,
-    {
-        unsafe {
-            core::slice::from_raw_parts_mut(
-                (self as *mut Self) as *mut u8,
-                ::core::mem::size_of::<Self>(),
-            )
+
+        if let Some(manifest) = manifest {
+            if verify_against_manifest(&entry.id, &entry.dtb, manifest) {
+                if to_stdout {
+                    eprintln!("\tverify: ok");
+                } else {
+                    println!("\tverify: ok");
+                }
+            } else {
+                all_ok = false;
+            }
         }
     }
-}
-
-type HeaderEntryV1 = HeaderEntry<4>;
-type HeaderEntryV2 = HeaderEntry<16>;
-
-impl AsByteSlice for Header {}
-impl AsByteSlice for DTHeader {}
-impl<const ID_SIZE: usize> AsByteSlice for HeaderEntry<ID_SIZE> {}
 
-trait SeekRead: Seek + Read {}
-impl<T: Seek + Read> SeekRead for T {}
+    Ok(all_ok)
+}
 
-fn dump_data<const ID_SIZE: usize>(
-    entries: u32,
-    dest: &str,
-    dtb: &mut dyn SeekRead,
-) -> io::Result<()> {
-    let mut headers: Vec<HeaderEntry<ID_SIZE>> = Vec::new();
+/// Recomputes the CRC32/SHA-1 of `data` and checks it against `id`'s entry in
+/// `manifest`, printing a diagnostic on mismatch. Returns whether it matched.
+fn verify_against_manifest(id: &str, data: &[u8], manifest: &HashMap<String, ManifestEntry>) -> bool {
+    let Some(entry) = manifest.get(id) else {
+        eprintln!("\tverify: MISMATCH, no manifest entry for {}", id);
+        return false;
+    };
 
-    for _ in 0..entries {
-        let mut h = HeaderEntry::<ID_SIZE>::new();
-        let h_bytes = h.as_mut_slice();
-        dtb.read_exact(h_bytes)?;
-        headers.push(h);
+    let (crc, sha1_hex) = hash_dtb(data);
+    if entry.dtb_size != data.len() || entry.crc32 != crc || entry.sha1 != sha1_hex {
+        eprintln!(
+            "\tverify: MISMATCH for {} (size {} vs {}, crc32 {:08x} vs {:08x})",
+            id,
+            data.len(),
+            entry.dtb_size,
+            crc,
+            entry.crc32
+        );
+        false
+    } else {
+        true
     }
+}
 
-    for h in headers.iter_mut() {
-        for chunk in h.soc.chunks_mut(4) {
-            chunk.reverse();
+/// Sniffs the leading bytes of `dtb` for a known compression magic and
+/// transparently inflates it, returning the raw (possibly still-compressed,
+/// if unrecognized) image bytes. The caller is responsible for validating
+/// the `AML_DT_HEADER` magic afterwards.
+fn sniff_and_decompress(mut dtb: File) -> io::Result<Vec<u8>> {
+    let mut magic_buf = [0u8; 6];
+    let n = dtb.read(&mut magic_buf)?;
+    dtb.seek(SeekFrom::Start(0))?;
+
+    let mut data = Vec::new();
+    if n >= GZIP_MAGIC.len() && magic_buf[..GZIP_MAGIC.len()] == GZIP_MAGIC {
+        let mut d = GzDecoder::new(dtb);
+        d.read_to_end(&mut data)
+            .expect("cannot decompress gzip file");
+    } else if n >= ZSTD_MAGIC.len() && magic_buf[..ZSTD_MAGIC.len()] == ZSTD_MAGIC {
+        #[cfg(feature = "compress-zstd")]
+        {
+            zstd::stream::copy_decode(dtb, &mut data).expect("cannot decompress zstd file");
         }
-        for chunk in h.plat.chunks_mut(4) {
-            chunk.reverse();
+        #[cfg(not(feature = "compress-zstd"))]
+        {
+            eprintln!("zstd support not compiled in (rebuild with --features compress-zstd)");
+            std::process::exit(1);
         }
-        for chunk in h.vari.chunks_mut(4) {
-            chunk.reverse();
+    } else if n >= XZ_MAGIC.len() && magic_buf[..XZ_MAGIC.len()] == XZ_MAGIC {
+        #[cfg(feature = "compress-xz")]
+        {
+            let mut d = xz2::read::XzDecoder::new(dtb);
+            d.read_to_end(&mut data)
+                .expect("cannot decompress xz file");
         }
-        let mut id = String::new();
-        id.push_str(str::from_utf8(&h.soc).unwrap().trim_end());
-        id.push('-');
-        id.push_str(str::from_utf8(&h.plat).unwrap().trim_end());
-        id.push('-');
-        id.push_str(str::from_utf8(&h.vari).unwrap().trim_end());
-
-        println!("Found header: {}", id);
-
-        dtb.seek(SeekFrom::Start(h.offset as u64))?;
-        let mut dtheader = DTHeader {
-            magic: 0,
-            totalsize: 0,
-        };
-        let dtheader_bytes = dtheader.as_mut_slice();
-        dtb.read_exact(dtheader_bytes)?;
-        if dtheader.magic != DT_HEADER_MAGIC {
-            println!("\tDTB Header mismatch. Found: {:x}", dtheader.magic);
-            continue;
+        #[cfg(not(feature = "compress-xz"))]
+        {
+            eprintln!("xz support not compiled in (rebuild with --features compress-xz)");
+            std::process::exit(1);
         }
-
-        dtheader.totalsize = u32::from_be(dtheader.totalsize);
-        println!("\t offset: {} size: {}", h.offset, dtheader.totalsize);
-
-        dtb.seek(SeekFrom::Start(h.offset as u64))?;
-        let mut data = vec![0; dtheader.totalsize as usize];
-        dtb.read_exact(&mut data)?;
-
-        let output_path = format!("{}{}.dtb", dest, id);
-        let mut output = File::create(output_path)?;
-        output.write_all(&data)?;
+    } else {
+        dtb.read_to_end(&mut data).expect("cannot read dtb file");
     }
 
-    Ok(())
+    Ok(data)
 }
 
 pub fn dtb_split(split_arg: &SplitArgs) -> io::Result<()> {
@@ -178,115 +253,234 @@ pub fn dtb_split(split_arg: &SplitArgs) -> io::Result<()> {
     }
 
     let boot_img_path = &split_arg.boot_img_path;
-    let dest = &split_arg.dest;
+    let selector = Selector::from_args(split_arg);
 
-    let mut dtb = File::open(boot_img_path)?;
-    let mut header = Header {
-        magic: 0,
-        version: 0,
-        entry_count: 0,
+    if split_arg.stdout && selector.is_none() {
+        eprintln!("--stdout requires --id (or --soc/--plat/--vari) to select an entry");
+        return Ok(());
+    }
+    if split_arg.dest.is_none() && !split_arg.stdout {
+        eprintln!("--dest is required unless --stdout is used");
+        return Ok(());
+    }
+    let dest = split_arg.dest.as_deref();
+
+    let dtb = File::open(boot_img_path)?;
+    let data = sniff_and_decompress(dtb)?;
+    let mut dtb_reader = io::Cursor::new(data);
+    let image = match AmlDtbImage::parse(&mut dtb_reader) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Invalid AML DTB header: {}", e);
+            return Ok(());
+        }
     };
 
-    let header_bytes = header.as_mut_slice();
+    let manifest = match &split_arg.verify {
+        Some(path) => Some(load_manifest(path)?),
+        None => None,
+    };
 
-    dtb.read_exact(header_bytes)?;
-    let mut dtb_reader;
-    
-    if header.magic != AML_DT_HEADER {
-        if header.magic & 0xffff == 0x8b1f {
-            dtb.seek(SeekFrom::Start(0 as u64))?;
+    // Once --stdout is in play, even this diagnostic has to go to stderr —
+    // it would otherwise prefix the piped DTB bytes.
+    if split_arg.stdout {
+        eprintln!(
+            "DTB Version: {} entries: {}",
+            image.version,
+            image.entries.len()
+        );
+    } else {
+        println!(
+            "DTB Version: {} entries: {}",
+            image.version,
+            image.entries.len()
+        );
+    }
 
-            let mut d = GzDecoder::new(dtb);
-            let mut data = Vec::new();
-            d.read_to_end(&mut data)
-                .expect("cannot decompression gzip file");
-
-            dtb_reader = io::Cursor::new(data);
-    
-            let header_bytes = header.as_mut_slice();
-            dtb_reader.read_exact(header_bytes)?;
-            if header.magic != AML_DT_HEADER {
-                eprintln!("Invalid AML DTB header.");
+    if let Some(sel) = &selector {
+        if sel.stdout {
+            let matches = image
+                .entries
+                .iter()
+                .filter(|entry| {
+                    let (soc, plat, vari) = split_id(&entry.id);
+                    sel.matches(soc, plat, vari, &entry.id)
+                })
+                .count();
+            if matches != 1 {
+                eprintln!(
+                    "--stdout requires the selector to match exactly one entry, found {}",
+                    matches
+                );
                 return Ok(());
             }
-        } else {
-            eprintln!("Invalid AML DTB header.");
-            return Ok(());
         }
-    } else {
-        let mut data = Vec::new();
-        dtb.read_to_end(&mut data)
-            .expect("cannot read dtb file");
-        dtb_reader = io::Cursor::new(data);
-    };
+    }
 
-    println!(
-        "DTB Version: {} entries: {}",
-        header.version, header.entry_count
-    );
+    let ok = dump_entries(&image, dest, manifest.as_ref(), selector.as_ref())?;
 
-    match header.version {
-        1 => dump_data::<4>(header.entry_count, &dest, &mut dtb_reader)?,
-        2 => dump_data::<16>(header.entry_count, &dest, &mut dtb_reader)?,
-        _ => {
-            eprintln!("Unrecognized DTB version");
-            return Ok(());
-        }
+    if !ok {
+        std::process::exit(1);
     }
 
     Ok(())
 }
 
-#[derive(Debug)]
-struct ChipInfo {
-    chipset: [u8; INFO_ENTRY_SIZE],
-    platform: [u8; INFO_ENTRY_SIZE],
-    rev_num: [u8; INFO_ENTRY_SIZE],
-    dtb_size: u32,
-    dtb_file: Vec<u8>,
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub(crate) struct InfoArgs {
+    #[arg(short, long)]
+    boot_img_path: String,
+
+    #[arg(long)]
+    verify: Option<String>,
 }
 
-impl ChipInfo {
-    fn new() -> Self {
-        Self {
-            chipset: [0; INFO_ENTRY_SIZE],
-            platform: [0; INFO_ENTRY_SIZE],
-            rev_num: [0; INFO_ENTRY_SIZE],
-            dtb_size: 0,
-            dtb_file: vec![],
+/// Prints one line per table-of-contents entry, optionally verifying each
+/// against a hash manifest. Reads bodies straight out of `dtb` by seeking to
+/// each entry's declared offset, the same image bytes `toc` was read from.
+fn dump_info<R: Read + Seek>(
+    toc: &[TocEntry],
+    dtb: &mut R,
+    manifest: Option<&HashMap<String, ManifestEntry>>,
+) -> io::Result<bool> {
+    let mut all_ok = true;
+
+    for t in toc {
+        let mut verify_status = "";
+        if let Some(manifest) = manifest {
+            if t.magic_ok {
+                dtb.seek(SeekFrom::Start(t.offset as u64))?;
+                let mut data = vec![0; t.totalsize as usize];
+                dtb.read_exact(&mut data)?;
+
+                if verify_against_manifest(&t.id, &data, manifest) {
+                    verify_status = " verify=ok";
+                } else {
+                    verify_status = " verify=MISMATCH";
+                    all_ok = false;
+                }
+            } else {
+                verify_status = " verify=skipped(bad-magic)";
+                all_ok = false;
+            }
         }
+
+        println!(
+            "{:<16} offset=0x{:08x} dtb_size={:<8} magic={:<3} totalsize={}{}",
+            t.id,
+            t.offset,
+            t.dtb_size,
+            if t.magic_ok { "ok" } else { "BAD" },
+            t.totalsize,
+            verify_status,
+        );
     }
+
+    Ok(all_ok)
 }
 
-fn pad_spaces(s: &mut [u8]) {
-    let len = s.len();
-    for i in (0..len).rev() {
-        if s[i] == 0 {
-            s[i] = b' ';
-        } else {
-            break;
+/// Dumps the table of contents of an AML DTB image without extracting any
+/// `.dtb` files, mirroring `dtb_split`'s header parsing.
+pub fn dtb_info(info_arg: &InfoArgs) -> io::Result<()> {
+    let dtb = File::open(&info_arg.boot_img_path)?;
+    let data = sniff_and_decompress(dtb)?;
+    let mut dtb_reader = io::Cursor::new(data);
+
+    let (version, toc) = match AmlDtbImage::read_toc(&mut dtb_reader) {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("Invalid AML DTB header: {}", e);
+            return Ok(());
         }
+    };
+
+    let manifest = match &info_arg.verify {
+        Some(path) => Some(load_manifest(path)?),
+        None => None,
+    };
+
+    println!("DTB Version: {} entries: {}", version, toc.len());
+
+    let ok = dump_info(&toc, &mut dtb_reader, manifest.as_ref())?;
+
+    if !ok {
+        std::process::exit(1);
     }
+
+    Ok(())
 }
 
-fn copy_str_to_cstr(dst: &mut [u8; INFO_ENTRY_SIZE], src: &str) {
-    for i in 0..min(src.len(), INFO_ENTRY_SIZE - 1) {
-        let c = src.chars().nth(i).unwrap();
-        if !c.is_ascii() || c.is_whitespace() {
-            break;
-        }
+/// Metadata for one `.dtb` discovered during pack's scanning pass: just
+/// enough to build the entry table and know where to re-read the file from
+/// during the streaming copy pass. The DTB body itself is never held
+/// alongside the others in memory.
+struct ChipMeta {
+    id: String,
+    path: path::PathBuf,
+    file_len: usize,
+    dtb_size: u32,
+}
 
-        dst[i] = c as u8
+/// One parsed line of a hash manifest: `soc-plat-vari  dtb_size  crc32  sha1`.
+struct ManifestEntry {
+    dtb_size: usize,
+    crc32: u32,
+    sha1: String,
+}
+
+fn hash_dtb(data: &[u8]) -> (u32, String) {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(data);
+
+    let mut sha1 = Sha1::new();
+    sha1.update(data);
+
+    (
+        crc.finalize(),
+        sha1.finalize().iter().map(|b| format!("{:02x}", b)).collect(),
+    )
+}
+
+fn write_manifest(path: &str, records: &[(String, usize, u32, String)]) -> io::Result<()> {
+    let mut f = File::create(path)?;
+    for (id, size, crc, sha1_hex) in records {
+        writeln!(f, "{}\t{}\t{:08x}\t{}", id, size, crc, sha1_hex)?;
     }
-    dst[INFO_ENTRY_SIZE - 1] = 0;
+    Ok(())
 }
 
-fn get_chip_info(filename: &str, page_size: usize) -> Option<ChipInfo> {
-    let mut input = fs::File::open(filename).unwrap();
+fn load_manifest(path: &str) -> io::Result<HashMap<String, ManifestEntry>> {
+    let content = fs::read_to_string(path)?;
+    let mut map = HashMap::new();
+    for line in content.lines() {
+        let parts: Vec<&str> = line.split('\t').collect();
+        if parts.len() != 4 {
+            continue;
+        }
+        map.insert(
+            parts[0].to_string(),
+            ManifestEntry {
+                dtb_size: parts[1].parse().unwrap_or(0),
+                crc32: u32::from_str_radix(parts[2], 16).unwrap_or(0),
+                sha1: parts[3].to_string(),
+            },
+        );
+    }
+    Ok(map)
+}
+
+/// Pass one: reads a `.dtb` just long enough to pull its `amlogic-dt-id`
+/// property out, then drops the buffer — only the resulting metadata is
+/// kept around for the rest of packing.
+fn scan_chip_meta(path: &path::Path, page_size: usize) -> Option<ChipMeta> {
+    let mut input = fs::File::open(path).unwrap();
     let mut buf = Vec::new();
     input.read_to_end(&mut buf).unwrap();
 
     let dt = device_tree::DeviceTree::load(buf.as_slice()).unwrap();
+    let file_len = buf.len();
+    drop(buf);
 
     if let Some(node) = dt.find("/") {
         match node.prop_str(DT_ID_TAG) {
@@ -295,14 +489,15 @@ fn get_chip_info(filename: &str, page_size: usize) -> Option<ChipInfo> {
 
                 if sp.len() != 3 {
                     eprintln!("cannot parse {}: {}", DT_ID_TAG, s);
+                    return None;
                 }
-                let mut chip = ChipInfo::new();
-                copy_str_to_cstr(&mut chip.chipset, sp[0]);
-                copy_str_to_cstr(&mut chip.platform, sp[1]);
-                copy_str_to_cstr(&mut chip.rev_num, sp[2]);
-                chip.dtb_size = (buf.len() + (page_size - (buf.len() % page_size))) as u32;
-                chip.dtb_file = buf;
-                return Some(chip);
+
+                return Some(ChipMeta {
+                    id: format!("{}-{}-{}", sp[0], sp[1], sp[2]),
+                    path: path.to_path_buf(),
+                    file_len,
+                    dtb_size: (file_len + (page_size - (file_len % page_size))) as u32,
+                });
             }
             Err(_) => {
                 eprintln!("cannot find {} in device tree", DT_ID_TAG);
@@ -311,10 +506,55 @@ fn get_chip_info(filename: &str, page_size: usize) -> Option<ChipInfo> {
         }
     }
 
-    println!("cannot find {} in {}", DT_ID_TAG, filename);
+    println!("cannot find {} in {}", DT_ID_TAG, path.display());
     None
 }
 
+/// Forwards writes to `inner` while accumulating a running CRC32/SHA-1, so
+/// pass two can hash each `.dtb` as it streams through `io::copy` instead of
+/// buffering it to hash separately.
+struct HashingWriter<W: Write> {
+    inner: W,
+    crc: crc32fast::Hasher,
+    sha1: Sha1,
+    len: usize,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            crc: crc32fast::Hasher::new(),
+            sha1: Sha1::new(),
+            len: 0,
+        }
+    }
+
+    fn finish(self) -> (usize, u32, String) {
+        let sha1_hex = self
+            .sha1
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        (self.len, self.crc.finalize(), sha1_hex)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc.update(&buf[..n]);
+        self.sha1.update(&buf[..n]);
+        self.len += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 pub fn dtb_pack(args: &PackArgs) {
     println!("DTB combiner:");
     println!("  Input directory: '{}'", args.input_dir);
@@ -324,15 +564,18 @@ pub fn dtb_pack(args: &PackArgs) {
     let out_file = &args.out_file;
 
     let filler = vec![0u8; page_size];
-    let mut chip_list: Vec<ChipInfo> = vec![];
+
+    // Pass one: scan every `.dtb` for its id and size, holding only metadata
+    // (not the file contents) for all of them at once.
+    let mut chip_metas: Vec<ChipMeta> = vec![];
 
     if let Ok(entries) = fs::read_dir(input_dir) {
         for entry in entries.flatten() {
             let path = entry.path();
             if path.extension().unwrap_or_default() == "dtb" {
                 println!("Found file: {:?}", path.file_name().unwrap());
-                if let Some(chip) = get_chip_info(path.to_str().unwrap(), page_size) {
-                    chip_list.push(chip);
+                if let Some(chip) = scan_chip_meta(&path, page_size) {
+                    chip_metas.push(chip);
                 } else {
                     println!("skip, failed to scan for '{}'", DT_ID_TAG);
                 }
@@ -340,83 +583,156 @@ pub fn dtb_pack(args: &PackArgs) {
         }
     }
 
-    let dtb_count = chip_list.len();
+    let dtb_count = chip_metas.len();
     println!("=> Found {} unique DTB(s)", dtb_count);
     if dtb_count == 0 {
-        chip_list.clear();
         return;
     }
 
-    let mut fp_out = File::create(path::PathBuf::from_str(&out_file).unwrap())
-        .expect("Error opening output file");
+    // Written straight to the (possibly compressed) output file, so pass two
+    // never holds more than one entry's bytes in memory at a time.
+    let mut out = OutputWriter::open(out_file, args.compress).expect("Error opening output file");
 
     let h = Header {
         magic: AML_DT_HEADER,
         version: AML_DT_VERSION,
         entry_count: dtb_count as u32,
     };
-    fp_out
-        .write_all(h.as_slice())
-        .expect("Error writing header");
+    h.write_to(&mut out).expect("Error writing header");
 
-    let mut dtb_offset = size_of::<Header>() + size_of::<HeaderEntryV2>() * dtb_count + 4;
+    let mut dtb_offset = table_size::<16>(dtb_count);
     let padding = page_size - (dtb_offset % page_size);
     dtb_offset += padding;
     let mut expected = dtb_offset;
 
-    for chip in chip_list.iter_mut() {
-        pad_spaces(&mut chip.chipset);
-        pad_spaces(&mut chip.platform);
-        pad_spaces(&mut chip.rev_num);
-
-        for chunk in chip.chipset.chunks_mut(4) {
-            chunk.reverse();
-        }
-        for chunk in chip.platform.chunks_mut(4) {
-            chunk.reverse();
-        }
-        for chunk in chip.rev_num.chunks_mut(4) {
-            chunk.reverse();
-        }
-        let entry = HeaderEntryV2 {
-            soc: chip.chipset,
-            plat: chip.platform,
-            vari: chip.rev_num,
-            offset: expected as u32,
-            dtb_size: chip.dtb_size,
-        };
-        fp_out
-            .write_all(entry.as_slice())
-            .expect("failed write entry header");
+    for chip in chip_metas.iter() {
+        let entry = HeaderEntry::<16>::from_id(&chip.id, expected as u32, chip.dtb_size)
+            .expect("invalid dtb id");
+        entry.write_to(&mut out).expect("failed write entry header");
 
         expected += chip.dtb_size as usize;
     }
 
     let rc: u32 = 0;
-    fp_out
-        .write(&rc.to_le_bytes())
-        .expect("cannot wirte status ");
+    out.write_all(&rc.to_le_bytes())
+        .expect("cannot write status");
 
     if padding > 0 {
-        fp_out
-            .write_all(&filler[0..padding])
+        out.write_all(&filler[0..padding])
             .expect("cannot write filler");
     }
 
-    for chip in chip_list.iter_mut() {
-        let dtb_buf = &chip.dtb_file;
-        io::copy(&mut io::Cursor::new(dtb_buf), &mut fp_out).expect("Error copying dtb file");
+    // Pass two: reopen each file in directory order and stream it straight
+    // into the output, so only one entry's bytes are ever in memory.
+    let mut manifest_records: Vec<(String, usize, u32, String)> = vec![];
+
+    for chip in chip_metas.iter() {
+        let mut input = File::open(&chip.path).expect("Error opening dtb file");
+        let mut hashing = HashingWriter::new(&mut out);
+        io::copy(&mut input, &mut hashing).expect("Error copying dtb file");
+        let (len, crc, sha1_hex) = hashing.finish();
 
-        let filler_size = page_size - (dtb_buf.len() % page_size);
+        if args.manifest.is_some() {
+            manifest_records.push((chip.id.clone(), len, crc, sha1_hex));
+        }
+
+        let filler_size = page_size - (chip.file_len % page_size);
         if filler_size > 0 && filler_size < page_size {
-            fp_out
-                .write_all(&filler[0..filler_size])
+            out.write_all(&filler[0..filler_size])
                 .expect("Error writing filler");
         }
     }
 
-    fp_out.flush().expect("cannot flush output");
-    drop(fp_out);
+    if let Some(manifest_path) = &args.manifest {
+        write_manifest(manifest_path, &manifest_records).expect("Error writing hash manifest");
+        println!("Wrote hash manifest to '{}'", manifest_path);
+    }
+
+    out.finish().expect("Error finishing output file");
 
     println!("Output written to '{}'", out_file);
 }
+
+/// A pack output file, optionally wrapped in a streaming compressor, so pass
+/// two of `dtb_pack` can write each `.dtb` straight through to disk instead of
+/// assembling the whole image in memory first.
+enum OutputWriter {
+    Raw(File),
+    Gzip(GzEncoder<File>),
+    #[cfg(feature = "compress-zstd")]
+    Zstd(Box<zstd::Encoder<'static, File>>),
+    #[cfg(feature = "compress-xz")]
+    Xz(xz2::write::XzEncoder<File>),
+}
+
+impl OutputWriter {
+    /// Opens `out_file`, wrapping it in the encoder `compress` calls for,
+    /// mirroring the magics `dtb_split` sniffs for on the way back in.
+    fn open(out_file: &str, compress: Compression) -> io::Result<Self> {
+        let out = File::create(path::PathBuf::from_str(out_file).unwrap())?;
+
+        Ok(match compress {
+            Compression::None => OutputWriter::Raw(out),
+            Compression::Gzip => OutputWriter::Gzip(GzEncoder::new(out, GzLevel::default())),
+            Compression::Zstd => {
+                #[cfg(feature = "compress-zstd")]
+                {
+                    OutputWriter::Zstd(Box::new(zstd::Encoder::new(out, 0)?))
+                }
+                #[cfg(not(feature = "compress-zstd"))]
+                {
+                    eprintln!("zstd support not compiled in (rebuild with --features compress-zstd)");
+                    std::process::exit(1);
+                }
+            }
+            Compression::Xz => {
+                #[cfg(feature = "compress-xz")]
+                {
+                    OutputWriter::Xz(xz2::write::XzEncoder::new(out, 6))
+                }
+                #[cfg(not(feature = "compress-xz"))]
+                {
+                    eprintln!("xz support not compiled in (rebuild with --features compress-xz)");
+                    std::process::exit(1);
+                }
+            }
+        })
+    }
+
+    /// Flushes and finalizes the underlying encoder (writing any trailer the
+    /// compressed format needs), consuming the writer.
+    fn finish(self) -> io::Result<()> {
+        match self {
+            OutputWriter::Raw(_) => Ok(()),
+            OutputWriter::Gzip(w) => w.finish().map(|_| ()),
+            #[cfg(feature = "compress-zstd")]
+            OutputWriter::Zstd(w) => w.finish().map(|_| ()),
+            #[cfg(feature = "compress-xz")]
+            OutputWriter::Xz(w) => w.finish().map(|_| ()),
+        }
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputWriter::Raw(w) => w.write(buf),
+            OutputWriter::Gzip(w) => w.write(buf),
+            #[cfg(feature = "compress-zstd")]
+            OutputWriter::Zstd(w) => w.write(buf),
+            #[cfg(feature = "compress-xz")]
+            OutputWriter::Xz(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::Raw(w) => w.flush(),
+            OutputWriter::Gzip(w) => w.flush(),
+            #[cfg(feature = "compress-zstd")]
+            OutputWriter::Zstd(w) => w.flush(),
+            #[cfg(feature = "compress-xz")]
+            OutputWriter::Xz(w) => w.flush(),
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! Reusable reader/writer for Amlogic's `AML_DT_HEADER` DTB container format,
+//! used by vendor bootloaders and update packages to bundle multiple
+//! per-board `.dtb` files into one `dtb.img`.
+//!
+//! The CLI in `dtb_tool` reads images through [`AmlDtbImage`] and adds the
+//! compression/hashing/selection conveniences that aren't part of the format
+//! itself. [`AmlDtbImage`] has no image writer: `dtb_tool`'s pack command
+//! lays out the image itself and writes it with [`Header`] and
+//! [`HeaderEntry`] (V2, 16-byte ids only).
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::str;
+
+pub const AML_DT_HEADER: u32 = 0x5f4c4d41;
+pub const DT_HEADER_MAGIC: u32 = 0xedfe0dd0;
+
+#[repr(C)]
+pub struct DTHeader {
+    pub magic: u32,
+    pub totalsize: u32,
+}
+
+#[repr(C)]
+pub struct Header {
+    pub magic: u32,
+    pub version: u32,
+    pub entry_count: u32,
+}
+
+#[repr(C)]
+pub struct HeaderEntry<const ID_SIZE: usize> {
+    pub soc: [u8; ID_SIZE],
+    pub plat: [u8; ID_SIZE],
+    pub vari: [u8; ID_SIZE],
+    pub offset: u32,
+    pub dtb_size: u32,
+}
+
+/// Parses a fixed-size AML structure from a byte stream, field by field, so
+/// the on-disk little-endian layout is honoured regardless of host
+/// endianness (unlike transmuting the struct directly).
+pub trait FromReader: Sized {
+    fn read_from<R: Read + ?Sized>(r: &mut R) -> io::Result<Self>;
+}
+
+/// The write-side counterpart of [`FromReader`].
+pub trait ToWriter {
+    fn write_to<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()>;
+}
+
+fn read_u32_le<R: Read + ?Sized>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn write_u32_le<W: Write + ?Sized>(w: &mut W, v: u32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+impl FromReader for Header {
+    fn read_from<R: Read + ?Sized>(r: &mut R) -> io::Result<Self> {
+        Ok(Self {
+            magic: read_u32_le(r)?,
+            version: read_u32_le(r)?,
+            entry_count: read_u32_le(r)?,
+        })
+    }
+}
+
+impl ToWriter for Header {
+    fn write_to<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        write_u32_le(w, self.magic)?;
+        write_u32_le(w, self.version)?;
+        write_u32_le(w, self.entry_count)
+    }
+}
+
+impl FromReader for DTHeader {
+    fn read_from<R: Read + ?Sized>(r: &mut R) -> io::Result<Self> {
+        let magic = read_u32_le(r)?;
+        // totalsize is deliberately stored big-endian in the DTB blob itself.
+        let mut totalsize_buf = [0u8; 4];
+        r.read_exact(&mut totalsize_buf)?;
+        Ok(Self {
+            magic,
+            totalsize: u32::from_be_bytes(totalsize_buf),
+        })
+    }
+}
+
+impl<const ID_SIZE: usize> FromReader for HeaderEntry<ID_SIZE> {
+    fn read_from<R: Read + ?Sized>(r: &mut R) -> io::Result<Self> {
+        let mut soc = [0u8; ID_SIZE];
+        let mut plat = [0u8; ID_SIZE];
+        let mut vari = [0u8; ID_SIZE];
+        r.read_exact(&mut soc)?;
+        r.read_exact(&mut plat)?;
+        r.read_exact(&mut vari)?;
+        let offset = read_u32_le(r)?;
+        let dtb_size = read_u32_le(r)?;
+        Ok(Self {
+            soc,
+            plat,
+            vari,
+            offset,
+            dtb_size,
+        })
+    }
+}
+
+impl<const ID_SIZE: usize> ToWriter for HeaderEntry<ID_SIZE> {
+    fn write_to<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.soc)?;
+        w.write_all(&self.plat)?;
+        w.write_all(&self.vari)?;
+        write_u32_le(w, self.offset)?;
+        write_u32_le(w, self.dtb_size)
+    }
+}
+
+/// Pads a fixed-size id field's trailing NUL bytes with spaces, matching
+/// what vendor-built images carry on disk.
+pub fn pad_spaces(s: &mut [u8]) {
+    let len = s.len();
+    for i in (0..len).rev() {
+        if s[i] == 0 {
+            s[i] = b' ';
+        } else {
+            break;
+        }
+    }
+}
+
+/// Copies as much of `src` as fits (minus the trailing NUL) into `dst`,
+/// stopping early on non-ASCII or whitespace.
+pub fn copy_str_to_cstr<const N: usize>(dst: &mut [u8; N], src: &str) {
+    for (i, c) in src.chars().enumerate() {
+        if i >= N - 1 || !c.is_ascii() || c.is_whitespace() {
+            break;
+        }
+        dst[i] = c as u8;
+    }
+}
+
+impl<const ID_SIZE: usize> HeaderEntry<ID_SIZE> {
+    /// Un-reverses the byte-swapped `soc`/`plat`/`vari` fields and returns
+    /// them as trimmed strings, for filenames and `--soc`/`--plat`/`--vari`
+    /// matching.
+    pub fn decode_parts(&mut self) -> (String, String, String) {
+        for chunk in self.soc.chunks_mut(4) {
+            chunk.reverse();
+        }
+        for chunk in self.plat.chunks_mut(4) {
+            chunk.reverse();
+        }
+        for chunk in self.vari.chunks_mut(4) {
+            chunk.reverse();
+        }
+
+        (
+            str::from_utf8(&self.soc).unwrap().trim_end().to_string(),
+            str::from_utf8(&self.plat).unwrap().trim_end().to_string(),
+            str::from_utf8(&self.vari).unwrap().trim_end().to_string(),
+        )
+    }
+
+    /// The joined `soc-plat-vari` id string used for filenames and `--id` matching.
+    pub fn decode_id(&mut self) -> String {
+        let (soc, plat, vari) = self.decode_parts();
+        format!("{}-{}-{}", soc, plat, vari)
+    }
+
+    /// Builds an entry from a `soc-plat-vari` id plus its placement in the
+    /// image, byte-swapping and space-padding the id fields the way the
+    /// on-disk format expects. The inverse of [`decode_id`](Self::decode_id).
+    pub fn from_id(id: &str, offset: u32, dtb_size: u32) -> io::Result<Self> {
+        let parts: Vec<&str> = id.splitn(3, '-').collect();
+        let [soc_str, plat_str, vari_str] = parts[..] else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid id '{}', expected soc-plat-vari", id),
+            ));
+        };
+
+        let mut soc = [0u8; ID_SIZE];
+        let mut plat = [0u8; ID_SIZE];
+        let mut vari = [0u8; ID_SIZE];
+        copy_str_to_cstr(&mut soc, soc_str);
+        copy_str_to_cstr(&mut plat, plat_str);
+        copy_str_to_cstr(&mut vari, vari_str);
+        pad_spaces(&mut soc);
+        pad_spaces(&mut plat);
+        pad_spaces(&mut vari);
+        for chunk in soc.chunks_mut(4) {
+            chunk.reverse();
+        }
+        for chunk in plat.chunks_mut(4) {
+            chunk.reverse();
+        }
+        for chunk in vari.chunks_mut(4) {
+            chunk.reverse();
+        }
+
+        Ok(Self {
+            soc,
+            plat,
+            vari,
+            offset,
+            dtb_size,
+        })
+    }
+}
+
+/// One decoded entry of an [`AmlDtbImage`]: its `soc-plat-vari` id, where its
+/// DTB blob sits in the image, and the blob itself.
+pub struct Entry {
+    pub id: String,
+    pub offset: u32,
+    pub size: u32,
+    pub dtb: Vec<u8>,
+}
+
+/// A parsed Amlogic multi-DTB image: the `AML_DT_HEADER` table of contents
+/// plus every entry's decoded DTB blob.
+pub struct AmlDtbImage {
+    pub version: u32,
+    pub entries: Vec<Entry>,
+}
+
+/// One entry of an image's table of contents as read by [`AmlDtbImage::read_toc`]:
+/// its decoded id and declared placement, plus whether the DTB magic at that
+/// offset actually validates. Unlike [`Entry`], this doesn't read the DTB
+/// body, and it reports bad-magic entries instead of skipping them — for
+/// diagnostic tooling that wants to show mismatches rather than hide them.
+pub struct TocEntry {
+    pub id: String,
+    pub offset: u32,
+    pub dtb_size: u32,
+    pub magic_ok: bool,
+    pub totalsize: u32,
+}
+
+impl AmlDtbImage {
+    /// Parses an image from `reader`, which must hold the `AML_DT_HEADER` at
+    /// stream position 0: entry offsets are seeked to as absolute positions
+    /// in the stream. Entries whose DTB magic doesn't
+    /// validate at the declared offset are skipped (with a diagnostic)
+    /// rather than failing the whole parse.
+    pub fn parse<R: Read + Seek>(reader: &mut R) -> io::Result<Self> {
+        let (version, toc) = Self::read_toc(reader)?;
+
+        let mut entries = Vec::new();
+        for t in toc {
+            if !t.magic_ok {
+                eprintln!("\tskipping {}: DTB header mismatch at offset {}", t.id, t.offset);
+                continue;
+            }
+
+            reader.seek(SeekFrom::Start(t.offset as u64))?;
+            let mut dtb = vec![0; t.totalsize as usize];
+            reader.read_exact(&mut dtb)?;
+
+            entries.push(Entry {
+                id: t.id,
+                offset: t.offset,
+                size: t.totalsize,
+                dtb,
+            });
+        }
+
+        Ok(Self { version, entries })
+    }
+
+    /// Reads just the header table of contents (not the DTB bodies),
+    /// reporting every entry including ones whose DTB magic doesn't
+    /// validate. Used by [`parse`](Self::parse) and by diagnostic tooling
+    /// (`dtb_tool`'s `info` subcommand) that wants to report mismatches
+    /// rather than silently skip them.
+    pub fn read_toc<R: Read + Seek>(reader: &mut R) -> io::Result<(u32, Vec<TocEntry>)> {
+        let header = Header::read_from(reader)?;
+        if header.magic != AML_DT_HEADER {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid AML DTB header",
+            ));
+        }
+
+        let toc = match header.version {
+            1 => Self::read_toc_entries::<4, R>(reader, header.entry_count)?,
+            2 => Self::read_toc_entries::<16, R>(reader, header.entry_count)?,
+            v => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unrecognized DTB version {}", v),
+                ))
+            }
+        };
+
+        Ok((header.version, toc))
+    }
+
+    fn read_toc_entries<const ID_SIZE: usize, R: Read + Seek>(
+        reader: &mut R,
+        entry_count: u32,
+    ) -> io::Result<Vec<TocEntry>> {
+        let mut headers: Vec<HeaderEntry<ID_SIZE>> = Vec::new();
+        for _ in 0..entry_count {
+            headers.push(HeaderEntry::<ID_SIZE>::read_from(reader)?);
+        }
+
+        let mut toc = Vec::new();
+        for h in headers.iter_mut() {
+            let id = h.decode_id();
+
+            reader.seek(SeekFrom::Start(h.offset as u64))?;
+            let dtheader = DTHeader::read_from(reader)?;
+
+            toc.push(TocEntry {
+                id,
+                offset: h.offset,
+                dtb_size: h.dtb_size,
+                magic_ok: dtheader.magic == DT_HEADER_MAGIC,
+                totalsize: dtheader.totalsize,
+            });
+        }
+
+        Ok(toc)
+    }
+
+}
+
+/// Byte offset where the DTB payload region begins in an on-disk image: the
+/// `Header`, `entry_count` `HeaderEntry<ID_SIZE>` records, and the trailing
+/// 4-byte status word — before page-alignment padding. `dtb_pack` computes
+/// it once and uses the padded result both as the first entry's offset and
+/// for the filler it writes after the table.
+pub fn table_size<const ID_SIZE: usize>(entry_count: usize) -> usize {
+    12 + (ID_SIZE * 3 + 8) * entry_count + 4
+}
@@ -1,7 +1,7 @@
 mod dtb_tool;
 use clap::{Parser, Subcommand};
 
-use dtb_tool::{PackArgs, SplitArgs};
+use dtb_tool::{InfoArgs, PackArgs, SplitArgs};
 
 #[derive(Parser)]
 #[command(version, about, long_about=None)]
@@ -14,6 +14,7 @@ struct Cli {
 enum Commands {
     Split(SplitArgs),
     Pack(PackArgs),
+    Info(InfoArgs),
 }
 
 fn main() {
@@ -21,8 +22,11 @@ fn main() {
 
     match &cli.command {
         Commands::Split(s) => {
-            let _ = dtb_tool::dtb_split(s).unwrap();
+            dtb_tool::dtb_split(s).unwrap();
         }
         Commands::Pack(p) => dtb_tool::dtb_pack(p),
+        Commands::Info(i) => {
+            dtb_tool::dtb_info(i).unwrap();
+        }
     }
 }